@@ -7,6 +7,8 @@ use std::f32::consts::PI;
 use hound::{WavSpec, SampleFormat, WavWriter};
 use bitwise::{BitReader, Bit};
 
+pub mod scale;
+
 const MIN_FILE_SIZE: u64 = 15;
 const BITS_PER_NOTE: u64 = 18;
 
@@ -17,12 +19,84 @@ const CHANNEL_COUNT: u16 = 1;       // Mono Audio
 const SAMPLING_RATE: u32 = 44_100;  // Sampling rate: CD Standard - 44.1kHz
 const BITS_PER_SAMPLE: u16 = 16;    // 8bit Mono Audio
 
-pub type MapToNote = fn (u8, u8, u8) -> Tone;
+const TICKS_PER_QUARTER: u16 = 480; // SMF division
+
+const DEFAULT_ATTACK_MS: f32 = 5.0;
+const DEFAULT_DECAY_MS: f32 = 5.0;
+const DEFAULT_SUSTAIN: f32 = 0.8;
+const DEFAULT_RELEASE_MS: f32 = 15.0;
+
+const METRONOME_FREQUENCY: f32 = 1000.0_f32;
+
+// Optional click track injected every beat during polyphonic rendering.
+#[derive(Debug, Clone, Copy)]
+pub struct Metronome {
+    pub bpm: u8,
+    pub volume: f32,
+}
+
+pub type MapToNote = Box<dyn Fn(u8, u8, u8) -> Tone>;
 
 #[derive(Debug)]
 pub struct Melody {
     pub bpm: u8,
     pub units: Vec<Tone>,
+    pub envelope: Envelope,
+}
+
+// Linear ADSR amplitude envelope, avoids clicks at note boundaries. Samples for attack/decay/release.
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope {
+    pub attack: u32,
+    pub decay: u32,
+    pub sustain: f32,
+    pub release: u32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope {
+            attack: ms_to_samples(DEFAULT_ATTACK_MS, SAMPLING_RATE),
+            decay: ms_to_samples(DEFAULT_DECAY_MS, SAMPLING_RATE),
+            sustain: DEFAULT_SUSTAIN,
+            release: ms_to_samples(DEFAULT_RELEASE_MS, SAMPLING_RATE),
+        }
+    }
+}
+
+impl Envelope {
+    // Shrinks attack/release proportionally when the note is shorter than attack + release,
+    // so a short note still ramps down instead of cutting off hard.
+    fn gain_at(&self, t: u32, steps: u32) -> f32 {
+        let total_ramp = self.attack.saturating_add(self.release);
+        let (attack, release) = if total_ramp > steps {
+            if total_ramp == 0 {
+                (0, 0)
+            } else {
+                let attack = (steps as u64 * self.attack as u64 / total_ramp as u64) as u32;
+                (attack, steps - attack)
+            }
+        } else {
+            (self.attack, self.release)
+        };
+        let decay = self.decay.min(steps.saturating_sub(attack + release));
+
+        if t < attack {
+            if attack == 0 { return 1.0; }
+            t as f32 / attack as f32
+        } else if t < attack + decay {
+            if decay == 0 { return self.sustain; }
+            let progress = (t - attack) as f32 / decay as f32;
+            1.0 - progress * (1.0 - self.sustain)
+        } else if t < steps.saturating_sub(release) {
+            self.sustain
+        } else if release == 0 {
+            0.0
+        } else {
+            let into_release = t - (steps - release);
+            self.sustain * (1.0 - into_release as f32 / release as f32)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -65,6 +139,18 @@ pub struct Tone {
     pub duration: Duration,
     pub volume: f32,
     pub frequency: f32,
+    pub voice: usize, // 0 in monophonic use
+    pub waveform: Waveform,
+}
+
+// Additive sums a_k * sin(k * phase) for the given per-harmonic amplitudes, normalized to [-1.0, 1.0].
+#[derive(Debug, Clone)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+    Additive(Vec<f32>),
 }
 
 impl From<u8> for Duration {
@@ -125,36 +211,164 @@ impl Tone {
             duration,
             volume,
             frequency,
+            voice: 0,
+            waveform: Waveform::Sine,
         }
     }
+
+    pub fn with_voice(mut self, voice: usize) -> Self {
+        self.voice = voice;
+        self
+    }
+
+    pub fn with_waveform(mut self, waveform: Waveform) -> Self {
+        self.waveform = waveform;
+        self
+    }
 }
 
 
+// Mirrors hound::WavSpec; describes the PCM layout write_melody should render to.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputSpec {
+    pub bits_per_sample: u16,
+    pub sample_format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Default for OutputSpec {
+    fn default() -> Self {
+        OutputSpec {
+            bits_per_sample: BITS_PER_SAMPLE,
+            sample_format: SampleFormat::Int,
+            channels: CHANNEL_COUNT,
+            sample_rate: SAMPLING_RATE,
+        }
+    }
+}
+
+impl From<OutputSpec> for WavSpec {
+    fn from(spec: OutputSpec) -> Self {
+        WavSpec {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            bits_per_sample: spec.bits_per_sample,
+            sample_format: spec.sample_format,
+        }
+    }
+}
+
 /**************************************************************************************************
                         Write Melody
  *************************************************************************************************/
-pub fn write_melody(melody: &Melody, path: &PathBuf) -> hound::Result<()> {
-    let spec = WavSpec {
-        channels: CHANNEL_COUNT,
-        sample_rate: SAMPLING_RATE,
-        bits_per_sample: BITS_PER_SAMPLE,
-        sample_format: SampleFormat::Int,
-    };
-
-    let mut writer = WavWriter::create(path, spec)?;
+pub fn write_melody(melody: &Melody, path: &PathBuf, spec: &OutputSpec) -> hound::Result<()> {
+    let mut writer = WavWriter::create(path, WavSpec::from(*spec))?;
     for tone in &melody.units {
-        write_tone(melody.bpm, &tone, &mut writer)?;
+        write_tone(melody.bpm, &tone, &melody.envelope, spec, &mut writer)?;
     }
 
     Ok(())
 }
 
+// Each voice plays on its own timeline; all voices are mixed into one buffer and normalized.
+pub fn write_melody_polyphonic(
+    melody: &Melody,
+    path: &PathBuf,
+    spec: &OutputSpec,
+    metronome: Option<Metronome>,
+) -> hound::Result<()> {
+    let mix = mix_polyphonic(melody, spec, metronome.as_ref());
+
+    let mut writer = WavWriter::create(path, WavSpec::from(*spec))?;
+    for sample in mix {
+        for _ in 0..spec.channels {
+            write_sample(&mut writer, spec, sample)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn mix_polyphonic(melody: &Melody, spec: &OutputSpec, metronome: Option<&Metronome>) -> Vec<f32> {
+    let voice_count = melody.units.iter().map(|t| t.voice).max().map_or(1, |m| m + 1);
+    let mut voice_offsets = vec![0_u32; voice_count];
+    let mut buffer_len = 0_u32;
+
+    for tone in &melody.units {
+        let steps = time_calc(melody.bpm, &tone.duration, spec.sample_rate);
+        let offset = voice_offsets[tone.voice];
+
+        voice_offsets[tone.voice] = offset + steps;
+        buffer_len = buffer_len.max(offset + steps);
+    }
+
+    let mut mix = vec![0.0_f32; buffer_len as usize];
+    let mut voice_offsets = vec![0_u32; voice_count];
+
+    for tone in &melody.units {
+        let steps = time_calc(melody.bpm, &tone.duration, spec.sample_rate);
+        let offset = voice_offsets[tone.voice];
+
+        for i in 0..steps {
+            let t = i as f32 / steps as f32;
+            let sample = oscillate(&tone.waveform, t * tone.frequency);
+            let gain = melody.envelope.gain_at(i, steps) * tone.volume;
+
+            mix[(offset + i) as usize] += sample * gain;
+        }
+
+        voice_offsets[tone.voice] = offset + steps;
+    }
+
+    if let Some(m) = metronome {
+        write_metronome_clicks(&mut mix, spec.sample_rate, m);
+    }
+
+    normalize(&mut mix);
+    mix
+}
+
+fn write_metronome_clicks(mix: &mut [f32], sample_rate: u32, metronome: &Metronome) {
+    let beat_samples = time_calc(metronome.bpm, &Duration::Quarter, sample_rate).max(1);
+    let click_len = (beat_samples / 8).max(1);
+
+    let mut offset = 0_usize;
+    while offset < mix.len() {
+        for i in 0..click_len as usize {
+            if offset + i >= mix.len() {
+                break;
+            }
+
+            let decay = 1.0 - (i as f32 / click_len as f32);
+            mix[offset + i] += (i as f32 * METRONOME_FREQUENCY * 2.0 * PI / sample_rate as f32).sin()
+                * metronome.volume
+                * decay;
+        }
+
+        offset += beat_samples as usize;
+    }
+}
+
+fn normalize(mix: &mut [f32]) {
+    let peak = mix.iter().fold(0.0_f32, |acc, &s| acc.max(s.abs()));
+
+    if peak > 1.0 {
+        for sample in mix.iter_mut() {
+            *sample /= peak;
+        }
+    }
+}
+
 pub fn write_for_arduino(melody: &Melody) -> String {
     let c = if melody.units.len() <= 100 {
         melody.units.len()
     } else { 100 };
 
     let mut output = String::new();
+    // The piezo buzzer only drives a square wave regardless of `Waveform`, so this is
+    // informational: it tells the reader what timbre the WAV render would use.
+    output.push_str(&format!("// waveform: {}\n", waveform_name(melody.units.first())));
     output.push_str(&*format!("int tone_count = {};\n", c));
     output.push_str(&*format!("int tones[{}] = {{", c));
 
@@ -168,6 +382,43 @@ pub fn write_for_arduino(melody: &Melody) -> String {
     output
 }
 
+pub fn write_for_midi(melody: &Melody) -> Vec<u8> {
+    let mut track = Vec::new();
+
+    let micros_per_quarter = 60_000_000u32 / melody.bpm as u32;
+    track.push(0x00); // delta-time
+    track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&micros_per_quarter.to_be_bytes()[1..]);
+
+    for tone in &melody.units {
+        let key = tone.pitch.min(127);
+        let velocity = ((tone.volume * 127.0) as u8).min(127);
+        let ticks = beats_to_ticks(&tone.duration);
+
+        track.push(0x00); // Note-On at delta 0
+        track.extend_from_slice(&[0x90, key, velocity]);
+
+        write_vlq(&mut track, ticks);
+        track.extend_from_slice(&[0x80, key, 0x00]);
+    }
+
+    track.push(0x00);
+    track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+    let mut output = Vec::new();
+    output.extend_from_slice(b"MThd");
+    output.extend_from_slice(&6u32.to_be_bytes());
+    output.extend_from_slice(&0u16.to_be_bytes()); // format 0
+    output.extend_from_slice(&1u16.to_be_bytes()); // ntrks
+    output.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+
+    output.extend_from_slice(b"MTrk");
+    output.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    output.extend_from_slice(&track);
+
+    output
+}
+
 pub fn write_for_json(melody: &Melody) -> String {
     let mut output = String::new();
     output.push('[');
@@ -184,24 +435,89 @@ pub fn write_for_json(melody: &Melody) -> String {
     output
 }
 
-fn write_tone<W: Write + Seek>(bpm: u8, tone: &Tone, writer: &mut WavWriter<W>) -> hound::Result<()> {
-    let steps = time_calc(bpm, &tone.duration);
+fn write_tone<W: Write + Seek>(
+    bpm: u8,
+    tone: &Tone,
+    envelope: &Envelope,
+    spec: &OutputSpec,
+    writer: &mut WavWriter<W>,
+) -> hound::Result<()> {
+    let steps = time_calc(bpm, &tone.duration, spec.sample_rate);
     // let steps: u32 = (60.0 / bpm as f32 * SAMPLING_RATE as f32) as u32;
-    let amplitude = i16::MAX as f32 * tone.volume;
 
-    for t in (0..steps).map(|x| x as f32 / steps as f32) {
-        let sample = (t * tone.frequency * 2.0 * PI).sin();
+    for i in 0..steps {
+        let t = i as f32 / steps as f32;
+        let sample = oscillate(&tone.waveform, t * tone.frequency);
+        let gain = envelope.gain_at(i, steps) * tone.volume;
 
-        writer.write_sample((sample * amplitude) as i16)?;
+        for _ in 0..spec.channels {
+            write_sample(writer, spec, sample * gain)?;
+        }
     }
 
     Ok(())
 }
 
-fn time_calc(bpm: u8, duration: &Duration) -> u32 {
+// cycles == frequency * t, matching the phase convention write_tone already used for sine.
+fn oscillate(waveform: &Waveform, cycles: f32) -> f32 {
+    match waveform {
+        Waveform::Sine => (cycles * 2.0 * PI).sin(),
+        Waveform::Square => (cycles * 2.0 * PI).sin().signum(),
+        Waveform::Sawtooth => 2.0 * (cycles - (cycles + 0.5).floor()),
+        Waveform::Triangle => {
+            let saw = 2.0 * (cycles - (cycles + 0.5).floor());
+            2.0 * saw.abs() - 1.0
+        }
+        Waveform::Additive(harmonics) => {
+            let peak: f32 = harmonics.iter().map(|a| a.abs()).sum::<f32>().max(1.0);
+            let sum: f32 = harmonics.iter().enumerate()
+                .map(|(k, a)| a * (cycles * (k as f32 + 1.0) * 2.0 * PI).sin())
+                .sum();
+
+            sum / peak
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EncodedSample {
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    F32(f32),
+}
+
+// Scales a [-1.0, 1.0] sample into the integer range for the spec's bit depth, or
+// leaves it untouched for float output.
+fn encode_sample(spec: &OutputSpec, sample: f32) -> EncodedSample {
+    match spec.sample_format {
+        SampleFormat::Float => EncodedSample::F32(sample),
+        SampleFormat::Int => match spec.bits_per_sample {
+            8 => EncodedSample::I8((sample * i8::MAX as f32) as i8),
+            16 => EncodedSample::I16((sample * i16::MAX as f32) as i16),
+            24 => EncodedSample::I32((sample * 8_388_607.0_f32) as i32),
+            _ => EncodedSample::I32((sample * i32::MAX as f32) as i32),
+        },
+    }
+}
+
+fn write_sample<W: Write + Seek>(writer: &mut WavWriter<W>, spec: &OutputSpec, sample: f32) -> hound::Result<()> {
+    match encode_sample(spec, sample) {
+        EncodedSample::I8(s) => writer.write_sample(s),
+        EncodedSample::I16(s) => writer.write_sample(s),
+        EncodedSample::I32(s) => writer.write_sample(s),
+        EncodedSample::F32(s) => writer.write_sample(s),
+    }
+}
+
+fn time_calc(bpm: u8, duration: &Duration, sample_rate: u32) -> u32 {
     let base: f32 = 60.0 / bpm as f32;
 
-    let beats: f32 = match duration {
+    (base * duration_to_beats(duration) * sample_rate as f32) as u32
+}
+
+fn duration_to_beats(duration: &Duration) -> f32 {
+    match duration {
         Duration::Double => 8.0,
         Duration::Whole => 4.0,
         Duration::Half => 2.0,
@@ -211,9 +527,11 @@ fn time_calc(bpm: u8, duration: &Duration) -> u32 {
         Duration::ThirtySecond => 1.0 / 8.0,
         Duration::SixtyFourth => 1.0 / 16.0,
         Duration::HundredTwentyEighth => 1.0 / 32.0,
-    };
+    }
+}
 
-    (base * beats * SAMPLING_RATE as f32) as u32
+fn beats_to_ticks(duration: &Duration) -> u32 {
+    (duration_to_beats(duration) * TICKS_PER_QUARTER as f32) as u32
 }
 
 
@@ -260,12 +578,39 @@ pub fn map_to_notes(path: &PathBuf, to_note: MapToNote) -> io::Result<Melody> {
         counter += BITS_PER_NOTE;
     }
 
-    Ok(Melody { bpm, units })
+    Ok(Melody { bpm, units, envelope: Envelope::default() })
 }
 
 /**************************************************************************************************
                         Utility Functions
  *************************************************************************************************/
+fn waveform_name(tone: Option<&Tone>) -> &'static str {
+    match tone.map(|t| &t.waveform) {
+        Some(Waveform::Sine) | None => "sine",
+        Some(Waveform::Square) => "square",
+        Some(Waveform::Triangle) => "triangle",
+        Some(Waveform::Sawtooth) => "sawtooth",
+        Some(Waveform::Additive(_)) => "additive",
+    }
+}
+
+fn ms_to_samples(ms: f32, sample_rate: u32) -> u32 {
+    (ms / 1000.0 * sample_rate as f32) as u32
+}
+
+fn write_vlq(buf: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    groups.reverse();
+    buf.extend_from_slice(&groups);
+}
+
 fn pack_to_byte(mut bits: Vec<Bit>) -> u8 {
     bits.reverse();
 
@@ -281,3 +626,126 @@ fn pack_to_byte(mut bits: Vec<Bit>) -> u8 {
 
     byte
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oscillate_sine_and_square_at_quarter_cycle() {
+        assert!((oscillate(&Waveform::Sine, 0.25) - 1.0).abs() < 1e-5);
+        assert_eq!(oscillate(&Waveform::Square, 0.25), 1.0);
+    }
+
+    #[test]
+    fn oscillate_sawtooth_and_triangle_at_half_cycle() {
+        assert!((oscillate(&Waveform::Sawtooth, 0.5) - -1.0).abs() < 1e-5);
+        assert!((oscillate(&Waveform::Triangle, 0.5) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn oscillate_additive_normalizes_to_peak() {
+        let gain = oscillate(&Waveform::Additive(vec![2.0, 0.0]), 0.25);
+
+        assert!((gain - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn encode_sample_scales_per_bit_depth() {
+        let spec_for = |bits| OutputSpec { bits_per_sample: bits, ..OutputSpec::default() };
+
+        assert_eq!(encode_sample(&spec_for(8), 1.0), EncodedSample::I8(i8::MAX));
+        assert_eq!(encode_sample(&spec_for(16), 1.0), EncodedSample::I16(i16::MAX));
+        assert_eq!(encode_sample(&spec_for(24), 1.0), EncodedSample::I32(8_388_607));
+        assert_eq!(encode_sample(&spec_for(32), 1.0), EncodedSample::I32(i32::MAX));
+    }
+
+    #[test]
+    fn encode_sample_float_format_is_not_rescaled() {
+        let spec = OutputSpec { sample_format: SampleFormat::Float, ..OutputSpec::default() };
+
+        assert_eq!(encode_sample(&spec, 0.5), EncodedSample::F32(0.5));
+    }
+
+    #[test]
+    fn write_vlq_single_byte() {
+        let mut buf = Vec::new();
+        write_vlq(&mut buf, 0x40);
+        assert_eq!(buf, vec![0x40]);
+    }
+
+    #[test]
+    fn write_vlq_multi_byte() {
+        let mut buf = Vec::new();
+        write_vlq(&mut buf, 0x3FFF);
+        assert_eq!(buf, vec![0xFF, 0x7F]);
+
+        let mut buf = Vec::new();
+        write_vlq(&mut buf, 480);
+        assert_eq!(buf, vec![0x83, 0x60]);
+    }
+
+    #[test]
+    fn gain_at_short_note_still_ramps_down() {
+        let envelope = Envelope { attack: 220, decay: 0, sustain: 0.8, release: 220 };
+        let steps = 150;
+
+        // Before the fix, attack greedily consumed the whole note and release
+        // collapsed to 0, so gain kept climbing toward 1.0 right up to the last sample.
+        assert!(envelope.gain_at(steps - 1, steps) < 0.1);
+    }
+
+    #[test]
+    fn gain_at_full_note_holds_sustain() {
+        let envelope = Envelope::default();
+        let steps = envelope.attack + envelope.decay + 200 + envelope.release;
+        let mid_sustain = envelope.attack + envelope.decay + 100;
+
+        assert_eq!(envelope.gain_at(mid_sustain, steps), envelope.sustain);
+    }
+
+    #[test]
+    fn parse_list_falls_back_on_empty_or_garbage_values() {
+        assert_eq!(scale::parse_list(Some(&"".to_string())), None);
+        assert_eq!(scale::parse_list(Some(&"x,y".to_string())), None);
+        assert_eq!(scale::parse_list(Some(&"1,2,3".to_string())), Some(vec![1, 2, 3]));
+        assert_eq!(scale::parse_list(None), None);
+    }
+
+    #[test]
+    fn mix_polyphonic_sizes_buffer_to_longest_voice() {
+        let melody = Melody {
+            bpm: 120,
+            units: vec![
+                Tone::new(60, 1, 255).with_voice(0),
+                Tone::new(64, 0, 255).with_voice(1),
+            ],
+            envelope: Envelope::default(),
+        };
+
+        let spec = OutputSpec::default();
+        let voice0_len = time_calc(melody.bpm, &melody.units[0].duration, spec.sample_rate);
+        let voice1_len = time_calc(melody.bpm, &melody.units[1].duration, spec.sample_rate);
+
+        let mix = mix_polyphonic(&melody, &spec, None);
+
+        assert_eq!(mix.len() as u32, voice0_len.max(voice1_len));
+    }
+
+    #[test]
+    fn mix_polyphonic_stays_within_normalized_range() {
+        let melody = Melody {
+            bpm: 200,
+            units: vec![
+                Tone::new(60, 3, 255).with_voice(0),
+                Tone::new(64, 3, 255).with_voice(1),
+                Tone::new(67, 3, 255).with_voice(2),
+            ],
+            envelope: Envelope::default(),
+        };
+
+        let mix = mix_polyphonic(&melody, &OutputSpec::default(), None);
+
+        assert!(mix.iter().all(|s| s.abs() <= 1.0));
+    }
+}
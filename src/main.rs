@@ -1,36 +1,132 @@
 use std::env;
+use std::fs;
 use std::process::exit;
-use musicbytes::{map_to_notes, Tone, write_melody, write_for_arduino, write_for_json};
+use musicbytes::{map_to_notes, write_melody, write_melody_polyphonic, write_for_arduino, write_for_json, write_for_midi, Metronome, OutputSpec};
+use musicbytes::scale::{Scale, waveform_by_name};
+use hound::SampleFormat;
 use std::path::PathBuf;
 
 const WAV_FILE: &'static str = "audio";
+const MIDI_FILE: &'static str = "audio";
+const DEFAULT_ROOT: u8 = 60; // Middle C
+const DEFAULT_SCALE: &'static str = "major";
+const DEFAULT_METRONOME_VOLUME: f32 = 0.3;
 
 enum OutputMode {
     WAV,
     Arduino,
-    JSON
+    JSON,
+    MIDI,
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
+    let mut positional = Vec::new();
+    let mut scale_name = DEFAULT_SCALE.to_string();
+    let mut scale_config: Option<PathBuf> = None;
+    let mut voices: Option<usize> = None;
+    let mut metronome = false;
+    let mut waveform_name: Option<String> = None;
+    let mut bits: Option<u16> = None;
+    let mut channels: Option<u16> = None;
+    let mut sample_rate: Option<u32> = None;
+    let mut float_format = false;
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--scale" {
+            scale_name = args.get(i + 1).unwrap_or_else(|| {
+                usage();
+                exit(1);
+            }).clone();
+            i += 2;
+        } else if args[i] == "--scale-config" {
+            scale_config = Some(PathBuf::from(args.get(i + 1).unwrap_or_else(|| {
+                usage();
+                exit(1);
+            })));
+            i += 2;
+        } else if args[i] == "--voices" {
+            voices = args.get(i + 1).and_then(|v| v.parse().ok());
+            i += 2;
+        } else if args[i] == "--metronome" {
+            metronome = true;
+            i += 1;
+        } else if args[i] == "--waveform" {
+            waveform_name = args.get(i + 1).cloned();
+            i += 2;
+        } else if args[i] == "--bits" {
+            bits = args.get(i + 1).and_then(|v| v.parse().ok());
+            i += 2;
+        } else if args[i] == "--channels" {
+            channels = args.get(i + 1).and_then(|v| v.parse().ok());
+            i += 2;
+        } else if args[i] == "--sample-rate" {
+            sample_rate = args.get(i + 1).and_then(|v| v.parse().ok());
+            i += 2;
+        } else if args[i] == "--float" {
+            float_format = true;
+            i += 1;
+        } else {
+            positional.push(args[i].clone());
+            i += 1;
+        }
+    }
+
+    if positional.len() != 2 {
         usage();
         exit(1);
     }
 
-    let output_mode = match args.get(1).unwrap().as_str() {
+    let output_mode = match positional[0].as_str() {
         "arduino" => OutputMode::Arduino,
         "json" => OutputMode::JSON,
         "wav" => OutputMode::WAV,
+        "midi" => OutputMode::MIDI,
         _ => {
             usage();
             exit(1);
         }
     };
 
-    let path = PathBuf::from(args.get(2).unwrap());
-    let res = map_to_notes(&path, c_major);
+    let mut scale = match scale_config {
+        Some(config_path) => Scale::from_config(&config_path).unwrap_or_else(|err| {
+            println!("Error reading scale config \'{}\': {}", config_path.to_str().unwrap(), err);
+            exit(1);
+        }),
+        None => Scale::by_name(&scale_name, DEFAULT_ROOT).unwrap_or_else(|| {
+            println!("Unknown scale '{}', falling back to '{}'", scale_name, DEFAULT_SCALE);
+            Scale::major(DEFAULT_ROOT)
+        }),
+    };
+    if let Some(voices) = voices {
+        scale.voices = voices;
+    }
+    if let Some(name) = waveform_name {
+        scale.waveform = waveform_by_name(&name).unwrap_or_else(|| {
+            println!("Unknown waveform '{}', keeping {:?}", name, scale.waveform);
+            scale.waveform.clone()
+        });
+    }
+
+    let mut output_spec = OutputSpec::default();
+    if float_format {
+        output_spec.sample_format = SampleFormat::Float;
+        output_spec.bits_per_sample = 32;
+    }
+    if let Some(bits) = bits {
+        output_spec.bits_per_sample = bits;
+    }
+    if let Some(channels) = channels {
+        output_spec.channels = channels;
+    }
+    if let Some(sample_rate) = sample_rate {
+        output_spec.sample_rate = sample_rate;
+    }
+
+    let scale_voices = scale.voices;
+    let path = PathBuf::from(&positional[1]);
+    let res = map_to_notes(&path, scale.into_map_to_note());
 
     let song = match res {
         Err(err) => {
@@ -45,7 +141,19 @@ fn main() {
             let mut path = PathBuf::new();
             path.push(WAV_FILE);
             path.set_extension("wav");
-            match write_melody(&song, &path) {
+
+            let result = if scale_voices > 1 || metronome {
+                let click = if metronome {
+                    Some(Metronome { bpm: song.bpm, volume: DEFAULT_METRONOME_VOLUME })
+                } else {
+                    None
+                };
+                write_melody_polyphonic(&song, &path, &output_spec, click)
+            } else {
+                write_melody(&song, &path, &output_spec)
+            };
+
+            match result {
                 Ok(_) => println!("Successfully created \'{}\'", path.to_str().unwrap()),
                 Err(err) => println!("Error creating \'{}\':\n{}", path.to_str().unwrap(), err),
             };
@@ -56,24 +164,22 @@ fn main() {
         OutputMode::JSON => {
             println!("{}", write_for_json(&song));
         }
+        OutputMode::MIDI => {
+            let mut path = PathBuf::new();
+            path.push(MIDI_FILE);
+            path.set_extension("mid");
+            match fs::write(&path, write_for_midi(&song)) {
+                Ok(_) => println!("Successfully created \'{}\'", path.to_str().unwrap()),
+                Err(err) => println!("Error creating \'{}\':\n{}", path.to_str().unwrap(), err),
+            };
+        }
     }
 }
 
 fn usage() {
-    println!("Usage: musicbytes [arduino/json/wav] FILE");
+    println!("Usage: musicbytes [arduino/json/wav/midi] [--scale NAME | --scale-config FILE] [--voices N] [--metronome] [--waveform NAME] [--bits N] [--channels N] [--sample-rate N] [--float] FILE");
+    println!("Scales: major, minor, pentatonic, chromatic, whole_tone");
+    println!("Waveforms: sine, square, triangle, sawtooth, additive:a1,a2,... (per-harmonic amplitudes)");
+    println!("--voices N and --metronome render wav output polyphonically");
+    println!("--bits/--channels/--sample-rate/--float control wav PCM layout (wav output only)");
 }
-
-// C, D, E, F, G, A
-pub fn c_major(pitch: u8, duration: u8, volume: u8) -> Tone {
-    let p = match pitch % 6 {
-        0 => 60,
-        1 => 62,
-        2 => 64,
-        3 => 65,
-        4 => 67,
-        5 => 69,
-        _ => 60,
-    };
-
-    Tone::new(p, duration, volume)
-}
\ No newline at end of file
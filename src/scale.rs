@@ -0,0 +1,124 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{MapToNote, Tone, Waveform};
+
+// Turns the raw pitch/duration/volume fields read by map_to_notes into an actual Tone.
+#[derive(Debug, Clone)]
+pub struct Scale {
+    pub root: u8,
+    pub degrees: Vec<u8>,
+    pub durations: Vec<u8>,
+    pub volumes: Vec<u8>,
+    pub voices: usize, // round-robins successive notes across this many voices
+    pub waveform: Waveform,
+}
+
+impl Scale {
+    pub fn major(root: u8) -> Self {
+        Scale { root, degrees: vec![0, 2, 4, 5, 7, 9, 11], durations: default_durations(), volumes: default_volumes(), voices: 1, waveform: Waveform::Sine }
+    }
+
+    pub fn natural_minor(root: u8) -> Self {
+        Scale { root, degrees: vec![0, 2, 3, 5, 7, 8, 10], durations: default_durations(), volumes: default_volumes(), voices: 1, waveform: Waveform::Sine }
+    }
+
+    pub fn pentatonic(root: u8) -> Self {
+        Scale { root, degrees: vec![0, 2, 4, 7, 9], durations: default_durations(), volumes: default_volumes(), voices: 1, waveform: Waveform::Sine }
+    }
+
+    pub fn chromatic(root: u8) -> Self {
+        Scale { root, degrees: (0..12).collect(), durations: default_durations(), volumes: default_volumes(), voices: 1, waveform: Waveform::Sine }
+    }
+
+    pub fn whole_tone(root: u8) -> Self {
+        Scale { root, degrees: vec![0, 2, 4, 6, 8, 10], durations: default_durations(), volumes: default_volumes(), voices: 1, waveform: Waveform::Sine }
+    }
+
+    pub fn by_name(name: &str, root: u8) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "major" => Some(Scale::major(root)),
+            "minor" | "natural_minor" => Some(Scale::natural_minor(root)),
+            "pentatonic" => Some(Scale::pentatonic(root)),
+            "chromatic" => Some(Scale::chromatic(root)),
+            "whole_tone" | "wholetone" => Some(Scale::whole_tone(root)),
+            _ => None,
+        }
+    }
+
+    // Simple key=value config file, one entry per line; missing fields fall back to defaults.
+    pub fn from_config(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut fields: HashMap<String, String> = HashMap::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                fields.insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let root = fields.get("root").and_then(|v| v.parse().ok()).unwrap_or(60);
+        let degrees = parse_list(fields.get("degrees")).unwrap_or_else(|| Scale::major(root).degrees);
+        let durations = parse_list(fields.get("durations")).unwrap_or_else(default_durations);
+        let volumes = parse_list(fields.get("volumes")).unwrap_or_else(default_volumes);
+        let voices = fields.get("voices").and_then(|v| v.parse().ok()).unwrap_or(1);
+        let waveform = fields.get("waveform").and_then(|v| waveform_by_name(v)).unwrap_or(Waveform::Sine);
+
+        Ok(Scale { root, degrees, durations, volumes, voices, waveform })
+    }
+
+    pub fn into_map_to_note(self) -> MapToNote {
+        let next_voice = Cell::new(0_usize);
+
+        Box::new(move |pitch, duration, volume| {
+            let degree = self.degrees[pitch as usize % self.degrees.len()];
+            let key = self.root.saturating_add(degree);
+            let dur = self.durations[duration as usize % self.durations.len()];
+            let vol = self.volumes[volume as usize % self.volumes.len()];
+
+            let voice = next_voice.get();
+            next_voice.set((voice + 1) % self.voices.max(1));
+
+            Tone::new(key, dur, vol).with_voice(voice).with_waveform(self.waveform.clone())
+        })
+    }
+}
+
+// "additive:a1,a2,..." selects Waveform::Additive with those per-harmonic amplitudes.
+pub fn waveform_by_name(name: &str) -> Option<Waveform> {
+    let name = name.trim();
+
+    if let Some(harmonics) = name.to_lowercase().strip_prefix("additive:") {
+        let amplitudes: Vec<f32> = harmonics.split(',').filter_map(|a| a.trim().parse().ok()).collect();
+        return if amplitudes.is_empty() { None } else { Some(Waveform::Additive(amplitudes)) };
+    }
+
+    match name.to_lowercase().as_str() {
+        "sine" => Some(Waveform::Sine),
+        "square" => Some(Waveform::Square),
+        "triangle" => Some(Waveform::Triangle),
+        "sawtooth" => Some(Waveform::Sawtooth),
+        _ => None,
+    }
+}
+
+fn default_durations() -> Vec<u8> {
+    vec![0, 1, 2, 3]
+}
+
+fn default_volumes() -> Vec<u8> {
+    vec![64, 128, 192, 255]
+}
+
+pub(crate) fn parse_list(value: Option<&String>) -> Option<Vec<u8>> {
+    let parsed: Vec<u8> = value?.split(',').filter_map(|n| n.trim().parse().ok()).collect();
+
+    if parsed.is_empty() { None } else { Some(parsed) }
+}